@@ -0,0 +1,128 @@
+//! Include/exclude glob and extension filtering for the whitelist set.
+
+use std::collections::HashSet;
+use std::process::Command;
+
+use failure::bail;
+use glob::Pattern;
+
+use crate::Result;
+
+/// Post-filtering applied to the closure computed by `get_needed_paths`.
+#[derive(Clone, Debug, Default)]
+pub struct Filters {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub allowed_ext: Vec<String>,
+    pub excluded_ext: Vec<String>,
+}
+
+impl Filters {
+    pub fn is_empty(&self) -> bool {
+        self.include.is_empty()
+            && self.exclude.is_empty()
+            && self.allowed_ext.is_empty()
+            && self.excluded_ext.is_empty()
+    }
+
+    /// Apply the include/exclude globs and extension filters to `needed`.
+    ///
+    /// A path that is filtered out is still kept if it is in the closure
+    /// of a path that actually survives filtering (referenced directly or
+    /// through other store paths), so excluding e.g.
+    /// `*-doc` never hides a directory something still in view requires
+    /// at runtime. Two excluded paths referencing each other (e.g. a
+    /// `-doc` output linking a `-man` output that's also excluded) do not
+    /// resurrect one another.
+    pub fn apply(&self, needed: &HashSet<String>) -> Result<HashSet<String>> {
+        if self.is_empty() {
+            return Ok(needed.clone());
+        }
+
+        let include = self
+            .include
+            .iter()
+            .map(|p| Pattern::new(p))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let exclude = self
+            .exclude
+            .iter()
+            .map(|p| Pattern::new(p))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+
+        let wanted: HashSet<String> = needed
+            .iter()
+            .filter(|name| self.matches(name, &include, &exclude))
+            .cloned()
+            .collect();
+
+        let protected = referenced_paths(&wanted)?;
+        Ok(wanted
+            .iter()
+            .cloned()
+            .chain(needed.intersection(&protected).cloned())
+            .collect())
+    }
+
+    fn matches(&self, name: &str, include: &[Pattern], exclude: &[Pattern]) -> bool {
+        if !include.is_empty() && !include.iter().any(|p| p.matches(name)) {
+            return false;
+        }
+        if exclude.iter().any(|p| p.matches(name)) {
+            return false;
+        }
+        let ext = extension(name);
+        if !self.allowed_ext.is_empty()
+            && !self
+                .allowed_ext
+                .iter()
+                .any(|e| ext.eq_ignore_ascii_case(e.trim_start_matches('.')))
+        {
+            return false;
+        }
+        if self
+            .excluded_ext
+            .iter()
+            .any(|e| ext.eq_ignore_ascii_case(e.trim_start_matches('.')))
+        {
+            return false;
+        }
+        true
+    }
+}
+
+/// The suffix after the last `.` in `name`, or `""` if there is none --
+/// so `--excluded-ext a` only matches genuine `.a` outputs, not every
+/// path whose name happens to end in the letter "a" (e.g. `...-1.2a`).
+fn extension(name: &str) -> &str {
+    match name.rfind('.') {
+        Some(i) => &name[i + 1..],
+        None => "",
+    }
+}
+
+/// Paths per `nix-store -q --requisites` invocation, kept well under the
+/// kernel's `ARG_MAX` even for a closure with thousands of entries.
+const REFERENCES_BATCH_SIZE: usize = 256;
+
+/// The closure of `paths`: every store path that any path in `paths`
+/// depends on, directly or transitively, i.e. the paths that must stay
+/// available for `paths` to work.
+fn referenced_paths(paths: &HashSet<String>) -> Result<HashSet<String>> {
+    let paths: Vec<&String> = paths.iter().collect();
+    let mut referenced = HashSet::new();
+    for batch in paths.chunks(REFERENCES_BATCH_SIZE) {
+        let output = Command::new("nix-store")
+            .arg("-q")
+            .arg("--requisites")
+            .args(batch.iter().map(|p| format!("/nix/store/{}", p)))
+            .output()?;
+        if !output.status.success() {
+            bail!("Failed to query nix store references");
+        }
+        let output = std::str::from_utf8(&output.stdout)?;
+        let len = "/nix/store/".len();
+        referenced.extend(output.lines().map(|l| l[len..].to_string()));
+    }
+    Ok(referenced)
+}
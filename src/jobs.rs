@@ -0,0 +1,89 @@
+//! A bounded worker pool for fanning out the whiteout/availability
+//! updates across `--jobs`/nproc workers, cooperating with an inherited
+//! `make` jobserver via `MAKEFLAGS` when one is available.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use failure::Error;
+use jobserver::Client;
+
+use crate::Result;
+
+pub struct Pool {
+    client: Client,
+    jobs: usize,
+}
+
+impl Pool {
+    /// Build a pool with `jobs` workers (defaulting to the number of
+    /// available CPUs), reusing the `make` jobserver passed down via
+    /// `MAKEFLAGS` instead of spawning a private one when possible.
+    pub fn new(jobs: Option<usize>) -> Result<Pool> {
+        let jobs = jobs.unwrap_or_else(|| {
+            thread::available_parallelism().map(|n| n.get()).unwrap_or(1)
+        });
+        let client = match unsafe { Client::from_env() } {
+            Some(client) => client,
+            None => Client::new(jobs)?,
+        };
+        Ok(Pool { client, jobs })
+    }
+
+    /// Run `work` for every item in `items` across up to `jobs`
+    /// concurrent workers, each gated on a jobserver token. Every item is
+    /// attempted even after a failure, but the first error encountered is
+    /// returned once all workers finish.
+    pub fn run<T, F>(&self, items: Vec<T>, work: F) -> Result<()>
+    where
+        T: Send + 'static,
+        F: Fn(&T) -> Result<()> + Send + Sync + 'static,
+    {
+        let items = Arc::new(Mutex::new(VecDeque::from(items)));
+        let work = Arc::new(work);
+        let first_error: Arc<Mutex<Option<Error>>> = Arc::new(Mutex::new(None));
+
+        // The invoking process already holds one implicit jobserver token
+        // (per the protocol, it's never supposed to acquire one for its
+        // own execution), so worker 0 runs on that token for free. Every
+        // other worker must acquire a token *before* it claims an item --
+        // acquiring after would let it pin an item to a thread that may
+        // then block forever under a jobserver with fewer spare tokens
+        // than workers, starving that item from ever being picked up by
+        // worker 0 once it has otherwise drained the queue.
+        let handles: Vec<_> = (0..self.jobs)
+            .map(|worker| {
+                let client = self.client.clone();
+                let items = Arc::clone(&items);
+                let work = Arc::clone(&work);
+                let first_error = Arc::clone(&first_error);
+                thread::spawn(move || loop {
+                    if items.lock().unwrap().is_empty() {
+                        break;
+                    }
+                    let _token = if worker == 0 { None } else { Some(client.acquire()) };
+                    let item = match items.lock().unwrap().pop_front() {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    if let Err(e) = work(&item) {
+                        let mut guard = first_error.lock().unwrap();
+                        if guard.is_none() {
+                            *guard = Some(e);
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            let _ = handle.join();
+        }
+
+        if let Some(e) = first_error.lock().unwrap().take() {
+            return Err(e);
+        }
+        Ok(())
+    }
+}
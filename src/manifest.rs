@@ -0,0 +1,133 @@
+//! Binary manifest of an effective store view: a fixed-size index laid
+//! out as an implicit binary-search tree (Eytzinger order) over the
+//! sorted path names, so a single lookup only needs `log2(n)` seeks
+//! instead of loading the whole file.
+//!
+//! Layout: `MAGIC` (8 bytes), path count as `u64`, then `count` index
+//! entries (`offset: u64, len: u32`, 12 bytes each, in Eytzinger order),
+//! followed by the path bytes themselves (also in Eytzinger order, each
+//! referenced by its index entry's offset/len into this blob).
+
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+use std::str;
+
+use failure::bail;
+
+use crate::Result;
+
+const MAGIC: &[u8; 8] = b"CSMANIF1";
+const INDEX_ENTRY_LEN: u64 = 12;
+
+/// Write `paths` to `output` as a manifest.
+pub fn write(output: &Path, paths: &HashSet<String>) -> Result<()> {
+    let mut sorted: Vec<&String> = paths.iter().collect();
+    sorted.sort();
+    let n = sorted.len();
+    let order = eytzinger_order(n);
+
+    let mut body = Vec::new();
+    let mut index = Vec::with_capacity(n);
+    for &rank in &order {
+        let bytes = sorted[rank].as_bytes();
+        index.push((body.len() as u64, bytes.len() as u32));
+        body.extend_from_slice(bytes);
+    }
+
+    let mut out = Vec::with_capacity(16 + index.len() * 12 + body.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&(n as u64).to_le_bytes());
+    for (offset, len) in &index {
+        out.extend_from_slice(&offset.to_le_bytes());
+        out.extend_from_slice(&len.to_le_bytes());
+    }
+    out.extend_from_slice(&body);
+    fs::write(output, out)?;
+    Ok(())
+}
+
+/// Load every path out of `manifest`, e.g. to rebuild an overlay with
+/// `--from-manifest` or to diff two snapshots.
+pub fn read_all(manifest: &Path) -> Result<HashSet<String>> {
+    let mut file = fs::File::open(manifest)?;
+    let n = read_header(&mut file)?;
+
+    let mut entries = Vec::with_capacity(n);
+    for k in 1..=n {
+        entries.push(read_index_entry(&mut file, 16, k)?);
+    }
+    let body_start = 16u64 + n as u64 * INDEX_ENTRY_LEN;
+
+    entries
+        .into_iter()
+        .map(|(offset, len)| read_body_entry(&mut file, body_start, offset, len))
+        .collect()
+}
+
+/// Print the paths added and removed between two manifests.
+pub fn diff(old: &Path, new: &Path) -> Result<()> {
+    let old_paths = read_all(old)?;
+    let new_paths = read_all(new)?;
+
+    let mut added: Vec<&String> = new_paths.difference(&old_paths).collect();
+    added.sort();
+    for path in added {
+        println!("+{}", path);
+    }
+
+    let mut removed: Vec<&String> = old_paths.difference(&new_paths).collect();
+    removed.sort();
+    for path in removed {
+        println!("-{}", path);
+    }
+    Ok(())
+}
+
+fn read_header(file: &mut fs::File) -> Result<usize> {
+    let mut magic = [0u8; 8];
+    file.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        bail!("Not a container-store manifest");
+    }
+    let mut count_buf = [0u8; 8];
+    file.read_exact(&mut count_buf)?;
+    Ok(u64::from_le_bytes(count_buf) as usize)
+}
+
+fn read_index_entry(file: &mut fs::File, index_start: u64, k: usize) -> Result<(u64, u32)> {
+    file.seek(SeekFrom::Start(index_start + (k - 1) as u64 * INDEX_ENTRY_LEN))?;
+    let mut entry = [0u8; INDEX_ENTRY_LEN as usize];
+    file.read_exact(&mut entry)?;
+    let offset = u64::from_le_bytes(entry[0..8].try_into().unwrap());
+    let len = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+    Ok((offset, len))
+}
+
+fn read_body_entry(file: &mut fs::File, body_start: u64, offset: u64, len: u32) -> Result<String> {
+    file.seek(SeekFrom::Start(body_start + offset))?;
+    let mut buf = vec![0u8; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(str::from_utf8(&buf)?.to_string())
+}
+
+/// The classic array layout for an implicit, pointerless binary search
+/// tree: `perm[k - 1]` is the sorted-order rank stored at 1-indexed BST
+/// node `k`, so a lookup can descend via `k = 2*k`/`k = 2*k + 1` instead
+/// of following pointers.
+fn eytzinger_order(n: usize) -> Vec<usize> {
+    let mut perm = vec![0usize; n];
+    fill(&mut perm, 0, 1, n);
+    perm
+}
+
+fn fill(perm: &mut [usize], i: usize, k: usize, n: usize) -> usize {
+    if k > n {
+        return i;
+    }
+    let i = fill(perm, i, 2 * k, n);
+    perm[k - 1] = i;
+    fill(perm, i + 1, 2 * k + 1, n)
+}
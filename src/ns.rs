@@ -0,0 +1,68 @@
+//! Namespace and capability setup for `--exec`: a private user namespace
+//! grants `CAP_SYS_ADMIN` over its own mount namespace without requiring
+//! real root, and once the mounts are in place the exec'd command gets
+//! no capabilities of its own.
+
+use std::fs;
+use std::path::Path;
+
+use failure::format_err;
+use nix::mount::{mount, MsFlags};
+use nix::sched::{unshare, CloneFlags};
+use nix::unistd::{getgid, getuid};
+
+use crate::Result;
+
+/// Unshare into a private user + mount namespace and map the calling
+/// user to root inside it, so the overlay mount made afterwards is only
+/// visible to this process tree.
+pub fn enter_rootless_namespaces() -> Result<()> {
+    let uid = getuid();
+    let gid = getgid();
+
+    unshare(CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS)
+        .map_err(|e| format_err!("Failed to unshare user/mount namespaces: {}", e))?;
+
+    // The kernel refuses to write the gid map unless setgroups is denied
+    // first, to stop an unprivileged process from dropping into groups it
+    // does not own.
+    fs::write("/proc/self/setgroups", "deny")?;
+    fs::write("/proc/self/uid_map", format!("0 {} 1", uid))?;
+    fs::write("/proc/self/gid_map", format!("0 {} 1", gid))?;
+
+    // Most distros mark the root mount MS_SHARED, which would propagate
+    // every mount we make afterwards straight back out to the host mount
+    // namespace. Recursively mark everything private so the overlay and
+    // the later bind onto /nix/store stay scoped to this process tree.
+    mount(
+        None::<&str>,
+        Path::new("/"),
+        None::<&str>,
+        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+        None::<&str>,
+    )
+    .map_err(|e| format_err!("Failed to make the mount namespace private: {}", e))?;
+
+    Ok(())
+}
+
+/// Drop every capability, including from the bounding set. Called once
+/// the overlay mount and the bind onto `/nix/store` are done, so the
+/// exec'd command starts with nothing: it never needed `CAP_SYS_ADMIN`
+/// or `CAP_SYS_CHROOT` itself, those were only needed to set the mounts
+/// up.
+pub fn drop_all_capabilities() -> Result<()> {
+    // We're still uid 0 inside the namespace, so the legacy root-exec
+    // rule would re-grant every capability left in the bounding set as
+    // permitted the moment we execve, regardless of what we clear below.
+    // Drop the bounding set to empty first.
+    for cap in capctl::caps::CapSet::full().iter() {
+        capctl::bounding::drop(cap)
+            .map_err(|e| format_err!("Failed to drop {:?} from the bounding set: {}", cap, e))?;
+    }
+
+    capctl::caps::CapState::empty()
+        .set_current()
+        .map_err(|e| format_err!("Failed to drop capabilities: {}", e))?;
+    Ok(())
+}
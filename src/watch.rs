@@ -0,0 +1,51 @@
+//! Daemon mode: an inotify watch on `/nix/store` triggers an incremental
+//! resync whenever paths appear or disappear, instead of requiring a
+//! restart to pick up new builds or a GC run.
+
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use inotify::{Inotify, WatchMask};
+
+use crate::filters::Filters;
+use crate::{sync_once, Result};
+
+/// Coalesce a burst of store events (a GC run deletes thousands of paths
+/// in quick succession) into a single resync.
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watch `/nix/store` for arriving or disappearing paths and re-run
+/// `sync_once` on every batch of changes, so the merged mount never
+/// serves a dangling entry without a full rebuild. Never returns.
+pub fn watch(
+    files: &[PathBuf],
+    upper_path: &Path,
+    filters: &Filters,
+    jobs: Option<usize>,
+    from_manifest: Option<&Path>,
+) -> Result<()> {
+    let mut inotify = Inotify::init()?;
+    inotify.add_watch(
+        "/nix/store",
+        WatchMask::CREATE | WatchMask::DELETE | WatchMask::MOVED_FROM | WatchMask::MOVED_TO,
+    )?;
+
+    let mut buffer = [0; 4096];
+    loop {
+        inotify.read_events_blocking(&mut buffer)?;
+        // Drain whatever else lands within the debounce window before
+        // recomputing, instead of resyncing once per event.
+        thread::sleep(DEBOUNCE);
+        while inotify.read_events(&mut buffer)?.next().is_some() {}
+
+        let (new_ctr, outdated_ctr, rm_ctr) =
+            sync_once(files, upper_path, filters, jobs, from_manifest)?;
+        if new_ctr + outdated_ctr + rm_ctr > 0 {
+            println!(
+                "Made {} paths available, removed {} outdated and {} unneeded paths",
+                new_ctr, outdated_ctr, rm_ctr
+            );
+        }
+    }
+}